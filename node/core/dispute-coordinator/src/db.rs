@@ -0,0 +1,246 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Persistent backing store for dispute votes.
+//!
+//! Votes are keyed by `(SessionIndex, CandidateHash)`. Alongside each vote we keep a
+//! per-session index of candidate hashes and an `earliest_session` watermark, so that pruning
+//! sessions older than the configured window only has to touch the entries that are actually
+//! being dropped rather than scanning the whole column.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use codec::{Decode, Encode};
+use kvdb::KeyValueDB;
+
+use polkadot_node_primitives::CandidateVotes;
+use polkadot_primitives::v1::{CandidateHash, SessionIndex};
+
+/// The single column this subsystem uses. Keys are namespaced by prefix (see `votes_key`,
+/// `session_index_key`, and `EARLIEST_SESSION_KEY`) rather than split across columns.
+const COL_DISPUTE_DATA: u32 = 0;
+
+/// Number of columns the dispute coordinator's database needs.
+pub const NUM_COLUMNS: u32 = 1;
+
+const EARLIEST_SESSION_KEY: &[u8] = b"earliest_session";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("io error accessing dispute-coordinator database: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("codec error decoding dispute-coordinator database entry: {0}")]
+	Codec(#[from] codec::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A backing store for candidate votes, keyed by `(SessionIndex, CandidateHash)`.
+///
+/// This is the persistent layer underneath the in-memory overlay kept by `State`: the overlay
+/// is written back here periodically (and on finalization), and reloaded from here on startup
+/// so votes survive a node restart.
+pub struct Backend {
+	db: Arc<dyn KeyValueDB>,
+}
+
+impl Backend {
+	/// Create a new backend from a raw key-value store opened with [`NUM_COLUMNS`] columns.
+	pub fn new(db: Arc<dyn KeyValueDB>) -> Self {
+		Backend { db }
+	}
+
+	/// Load a single candidate's votes, if present.
+	pub fn load_votes(&self, session: SessionIndex, candidate_hash: &CandidateHash)
+		-> Result<Option<CandidateVotes>>
+	{
+		match self.db.get(COL_DISPUTE_DATA, &votes_key(session, candidate_hash))? {
+			Some(raw) => Ok(Some(CandidateVotes::decode(&mut &raw[..])?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Load the set of candidate hashes known for `session`.
+	pub fn load_session_candidates(&self, session: SessionIndex) -> Result<Vec<CandidateHash>> {
+		match self.db.get(COL_DISPUTE_DATA, &session_index_key(session))? {
+			Some(raw) => Ok(Decode::decode(&mut &raw[..])?),
+			None => Ok(Vec::new()),
+		}
+	}
+
+	/// Load the earliest session still retained in the database, if any has been recorded.
+	pub fn load_earliest_session(&self) -> Result<Option<SessionIndex>> {
+		match self.db.get(COL_DISPUTE_DATA, EARLIEST_SESSION_KEY)? {
+			Some(raw) => Ok(Some(Decode::decode(&mut &raw[..])?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Write every entry of `overlay` back to disk, then prune any session older than
+	/// `highest_session - window`. Entries of `overlay` that are already older than that are
+	/// dropped rather than written.
+	///
+	/// `overlay` is expected to hold only the entries that changed since the last write (see
+	/// `State::flush`), not the full in-memory working set, so this stays proportional to what
+	/// actually changed rather than the whole retained `DISPUTE_WINDOW`.
+	///
+	/// Pruning only touches the candidate hashes indexed under each dropped session, so its
+	/// cost is proportional to the number of entries actually removed.
+	pub fn write_overlay(
+		&self,
+		overlay: &HashMap<(SessionIndex, CandidateHash), CandidateVotes>,
+		highest_session: SessionIndex,
+		window: SessionIndex,
+	) -> Result<()> {
+		let mut tx = self.db.transaction();
+		let new_earliest_session = highest_session.saturating_sub(window);
+
+		let mut touched_sessions: HashMap<SessionIndex, Vec<CandidateHash>> = HashMap::new();
+		for ((session, candidate_hash), votes) in overlay {
+			if *session < new_earliest_session {
+				continue;
+			}
+			tx.put_vec(COL_DISPUTE_DATA, &votes_key(*session, candidate_hash), votes.encode());
+			touched_sessions.entry(*session).or_default().push(*candidate_hash);
+		}
+		for (session, new_hashes) in touched_sessions {
+			let mut all_hashes = self.load_session_candidates(session)?;
+			all_hashes.extend(new_hashes);
+			all_hashes.sort();
+			all_hashes.dedup();
+			tx.put_vec(COL_DISPUTE_DATA, &session_index_key(session), all_hashes.encode());
+		}
+
+		// With no watermark recorded yet, nothing older than the window has been written (see
+		// above), so there is nothing to prune; just record the watermark.
+		let earliest_session = self.load_earliest_session()?;
+		if earliest_session.map_or(true, |earliest| new_earliest_session > earliest) {
+			for pruned_session in earliest_session.unwrap_or(new_earliest_session)..new_earliest_session {
+				for candidate_hash in self.load_session_candidates(pruned_session)? {
+					tx.delete(COL_DISPUTE_DATA, &votes_key(pruned_session, &candidate_hash));
+				}
+				tx.delete(COL_DISPUTE_DATA, &session_index_key(pruned_session));
+			}
+			tx.put_vec(COL_DISPUTE_DATA, EARLIEST_SESSION_KEY, new_earliest_session.encode());
+		}
+
+		self.db.write(tx).map_err(Into::into)
+	}
+
+	/// Reload the overlay for every session between the persisted `earliest_session` (falling
+	/// back to `highest_session - window` if none has been recorded yet) and `highest_session`.
+	pub fn load_overlay(&self, highest_session: SessionIndex, window: SessionIndex)
+		-> Result<HashMap<(SessionIndex, CandidateHash), CandidateVotes>>
+	{
+		let earliest_session = self.load_earliest_session()?
+			.unwrap_or_else(|| highest_session.saturating_sub(window));
+
+		let mut overlay = HashMap::new();
+		for session in earliest_session..=highest_session {
+			for candidate_hash in self.load_session_candidates(session)? {
+				if let Some(votes) = self.load_votes(session, &candidate_hash)? {
+					overlay.insert((session, candidate_hash), votes);
+				}
+			}
+		}
+		Ok(overlay)
+	}
+}
+
+fn votes_key(session: SessionIndex, candidate_hash: &CandidateHash) -> Vec<u8> {
+	(b"votes", session, candidate_hash).encode()
+}
+
+fn session_index_key(session: SessionIndex) -> Vec<u8> {
+	(b"session_index", session).encode()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::H256;
+
+	fn backend() -> Backend {
+		Backend::new(Arc::new(kvdb_memorydb::create(NUM_COLUMNS)))
+	}
+
+	fn candidate(byte: u8) -> CandidateHash {
+		CandidateHash(H256::repeat_byte(byte))
+	}
+
+	#[test]
+	fn round_trips_votes_through_write_and_load() {
+		let backend = backend();
+		let mut overlay = HashMap::new();
+		overlay.insert((1, candidate(1)), CandidateVotes::default());
+		overlay.insert((1, candidate(2)), CandidateVotes::default());
+
+		backend.write_overlay(&overlay, 1, DISPUTE_WINDOW_FOR_TESTS).unwrap();
+
+		assert_eq!(backend.load_votes(1, &candidate(1)).unwrap(), Some(CandidateVotes::default()));
+		assert_eq!(backend.load_votes(1, &candidate(2)).unwrap(), Some(CandidateVotes::default()));
+		assert_eq!(backend.load_votes(1, &candidate(3)).unwrap(), None);
+
+		let mut candidates = backend.load_session_candidates(1).unwrap();
+		candidates.sort();
+		let mut expected = vec![candidate(1), candidate(2)];
+		expected.sort();
+		assert_eq!(candidates, expected);
+	}
+
+	#[test]
+	fn write_overlay_prunes_sessions_older_than_window() {
+		let backend = backend();
+
+		let mut old_overlay = HashMap::new();
+		old_overlay.insert((1, candidate(1)), CandidateVotes::default());
+		backend.write_overlay(&old_overlay, 1, 2).unwrap();
+
+		// Advancing far enough that session 1 falls outside the window should prune it and bump
+		// `earliest_session`, even though this write doesn't touch session 1 itself.
+		backend.write_overlay(&HashMap::new(), 10, 2).unwrap();
+
+		assert_eq!(backend.load_votes(1, &candidate(1)).unwrap(), None);
+		assert!(backend.load_session_candidates(1).unwrap().is_empty());
+		assert_eq!(backend.load_earliest_session().unwrap(), Some(8));
+	}
+
+	#[test]
+	fn load_overlay_reloads_only_the_retained_window() {
+		let backend = backend();
+
+		let mut overlay = HashMap::new();
+		overlay.insert((1, candidate(1)), CandidateVotes::default());
+		overlay.insert((5, candidate(2)), CandidateVotes::default());
+		backend.write_overlay(&overlay, 5, 2).unwrap();
+
+		// Session 1 was outside the window as of the write above (earliest_session = 3) and so
+		// was pruned; only session 5 should come back.
+		let reloaded = backend.load_overlay(5, 2).unwrap();
+		assert_eq!(reloaded.len(), 1);
+		assert!(reloaded.contains_key(&(5, candidate(2))));
+	}
+
+	#[test]
+	fn load_overlay_on_empty_database_returns_empty_map() {
+		let backend = backend();
+		let reloaded = backend.load_overlay(5, DISPUTE_WINDOW_FOR_TESTS).unwrap();
+		assert!(reloaded.is_empty());
+	}
+
+	const DISPUTE_WINDOW_FOR_TESTS: SessionIndex = 6;
+}
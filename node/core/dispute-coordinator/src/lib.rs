@@ -25,25 +25,423 @@
 //! another node, this will trigger the dispute participation subsystem to recover and validate the block and call
 //! back to this subsystem.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use polkadot_node_primitives::CandidateVotes;
+use futures::channel::oneshot;
+use futures::prelude::*;
+
+use sp_runtime::traits::Block as BlockT;
+
+use polkadot_node_primitives::{CandidateVotes, SignedDisputeStatement};
 use polkadot_node_subsystem::{
 	messages::{
-		RuntimeApiRequest, DisputeCoordinatorMessage,
+		RuntimeApiMessage, RuntimeApiRequest, DisputeCoordinatorMessage,
 	},
-	Subsystem, SubsystemContext, SubsystemResult, FromOverseer, OverseerSignal, SpawnedSubsystem,
-	SubsystemError,
+	AllMessages, Subsystem, SubsystemContext, SubsystemResult, FromOverseer, OverseerSignal,
+	SpawnedSubsystem, SubsystemError,
+};
+use polkadot_primitives::v1::{
+	CandidateReceipt, DisputeStatement, InvalidDisputeStatementKind, SessionIndex, CandidateHash,
+	ValidDisputeStatementKind,
 };
-use polkadot_primitives::v1::{SessionIndex, CandidateHash};
 
+use kvdb::KeyValueDB;
 use sc_keystore::LocalKeystore;
+use sc_transaction_pool_api::OffchainTransactionPoolFactory;
 
 mod db;
 
-struct State {
+const LOG_TARGET: &str = "dispute_coordinator";
+
+/// Number of recent sessions for which votes are retained. Anything older is pruned from the
+/// overlay when a newer session is noted, and from the backing database the next time the
+/// overlay is flushed.
+const DISPUTE_WINDOW: SessionIndex = 6;
+
+/// Prune `overlay` and advance `earliest_session` for a new high-water mark `session`.
+///
+/// Pulled out of `State::note_new_session` as a free function over plain windowing state (no
+/// keystore, database handle, or transaction pool involved) so the pruning math is testable on
+/// its own.
+fn prune_overlay_for_new_session(
+	overlay: &mut HashMap<(SessionIndex, CandidateHash), CandidateVotes>,
+	highest_session: &mut SessionIndex,
+	earliest_session: &mut SessionIndex,
+	session: SessionIndex,
+) {
+	if session <= *highest_session {
+		return;
+	}
+	*highest_session = session;
+
+	let new_earliest_session = session.saturating_sub(DISPUTE_WINDOW);
+	if new_earliest_session > *earliest_session {
+		overlay.retain(|(session, _), _| *session >= new_earliest_session);
+		*earliest_session = new_earliest_session;
+	}
+}
+
+struct State<Block: BlockT> {
 	keystore: Arc<LocalKeystore>,
+	db: db::Backend,
+	/// Write-back cache over `db`: the source of truth for any session still in this process's
+	/// memory, flushed out on `OverseerSignal::BlockFinalized` and reloaded from `db` at
+	/// startup so votes survive a restart.
 	overlay: HashMap<(SessionIndex, CandidateHash), CandidateVotes>,
+	/// Keys imported into `overlay` since the last successful `flush`. `flush` only needs to
+	/// write these back to `db` rather than the whole overlay, which would otherwise re-encode
+	/// and rewrite the full `DISPUTE_WINDOW` of retained votes on every finalized block.
+	dirty: HashSet<(SessionIndex, CandidateHash)>,
 	highest_session: SessionIndex,
+	/// The oldest session whose votes are still retained in the overlay. Bumped in lockstep with
+	/// overlay pruning, which keeps only entries at or above the new value.
+	earliest_session: SessionIndex,
+	/// Handle used to submit locally-signed dispute statements as offchain transactions, so
+	/// disputes initiated by this node reach the rest of the network instead of staying
+	/// node-local in the overlay.
+	tx_pool_factory: OffchainTransactionPoolFactory<Block>,
+	/// Builds the runtime-specific extrinsic wrapping a locally-signed dispute statement.
+	extrinsic_builder: Arc<dyn DisputeStatementExtrinsicBuilder<Block>>,
+}
+
+impl<Block: BlockT> State<Block> {
+	/// Construct a fresh `State`, reloading any votes the configured `DISPUTE_WINDOW` still
+	/// retains from `db`.
+	///
+	/// A failure to read persisted state is logged and treated as an empty overlay rather than
+	/// failing subsystem startup outright, since the overlay will simply be repopulated (and
+	/// the database repaired) as new votes and finalizations arrive.
+	fn new(
+		keystore: Arc<LocalKeystore>,
+		db: Arc<dyn KeyValueDB>,
+		tx_pool_factory: OffchainTransactionPoolFactory<Block>,
+		extrinsic_builder: Arc<dyn DisputeStatementExtrinsicBuilder<Block>>,
+		highest_session: SessionIndex,
+	) -> Self {
+		let db = db::Backend::new(db);
+		let earliest_session = highest_session.saturating_sub(DISPUTE_WINDOW);
+
+		let (overlay, earliest_session) = match db.load_overlay(highest_session, DISPUTE_WINDOW) {
+			Ok(overlay) => {
+				let earliest_session = db.load_earliest_session().unwrap_or(None)
+					.unwrap_or(earliest_session);
+				(overlay, earliest_session)
+			},
+			Err(err) => {
+				tracing::warn!(
+					target: LOG_TARGET,
+					?err,
+					"failed to reload persisted dispute votes; starting from an empty overlay",
+				);
+				(HashMap::new(), earliest_session)
+			},
+		};
+
+		State {
+			keystore, db, overlay, dirty: HashSet::new(), highest_session, earliest_session,
+			tx_pool_factory, extrinsic_builder,
+		}
+	}
+
+	/// Record a vote in the overlay. The overlay is the source of truth until the next flush.
+	fn import_vote(&mut self, session: SessionIndex, candidate_hash: CandidateHash, votes: CandidateVotes) {
+		self.overlay.insert((session, candidate_hash), votes);
+		self.dirty.insert((session, candidate_hash));
+	}
+
+	/// Advance `highest_session`, pruning overlay entries that fall outside `DISPUTE_WINDOW` of
+	/// the new high-water mark.
+	fn note_new_session(&mut self, session: SessionIndex) {
+		prune_overlay_for_new_session(
+			&mut self.overlay,
+			&mut self.highest_session,
+			&mut self.earliest_session,
+			session,
+		);
+		// A pruned key is gone from the overlay entirely; nothing is left to flush for it.
+		self.dirty.retain(|key| self.overlay.contains_key(key));
+	}
+
+	/// Write back to `db` whatever was imported into the overlay since the last flush, which
+	/// also prunes any session older than `DISPUTE_WINDOW`.
+	///
+	/// Only the entries touched since the last flush are re-encoded and written, rather than the
+	/// entire overlay: since this runs on every `OverseerSignal::BlockFinalized`, rewriting the
+	/// full `DISPUTE_WINDOW` of retained votes on every block would be wasted work for the
+	/// (typically much smaller) set of votes actually imported since the last flush.
+	fn flush(&mut self) -> Result<(), db::Error> {
+		let changed: HashMap<_, _> = self.dirty.iter()
+			.filter_map(|key| self.overlay.get(key).map(|votes| (*key, votes.clone())))
+			.collect();
+
+		self.db.write_overlay(&changed, self.highest_session, DISPUTE_WINDOW)?;
+		self.dirty.clear();
+		Ok(())
+	}
+
+	/// Sign a dispute statement produced by local validation, record it in the overlay, and
+	/// submit it as an offchain transaction so the vote also reaches the rest of the network
+	/// rather than only mutating the overlay.
+	fn sign_and_submit_local_statement(
+		&mut self,
+		at: Block::Hash,
+		session: SessionIndex,
+		candidate_receipt: CandidateReceipt,
+		valid: bool,
+	) {
+		let candidate_hash = candidate_receipt.hash();
+		let kind = explicit_dispute_statement_kind(valid);
+
+		let signed = match SignedDisputeStatement::sign_explicit(&self.keystore, kind, candidate_hash, session) {
+			Ok(Some(signed)) => signed,
+			Ok(None) => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					?candidate_hash,
+					"no local key controls a seat in this session; nothing to sign",
+				);
+				return;
+			},
+			Err(err) => {
+				tracing::warn!(target: LOG_TARGET, ?err, ?candidate_hash, "failed to sign local dispute statement");
+				return;
+			},
+		};
+
+		// Record the vote locally before broadcasting it, so this node's own overlay (and, on
+		// the next flush, its database) reflects its vote immediately rather than only learning
+		// about it once the statement comes back from the network.
+		let mut votes = self.overlay.get(&(session, candidate_hash))
+			.cloned()
+			.unwrap_or_else(|| CandidateVotes::new(candidate_receipt.clone()));
+		votes.add_statement(signed.clone());
+		self.import_vote(session, candidate_hash, votes);
+
+		let extrinsic = self.extrinsic_builder.build(signed, candidate_receipt);
+		if self.tx_pool_factory.offchain_transaction_pool(at).submit_transaction(extrinsic).is_err() {
+			tracing::warn!(
+				target: LOG_TARGET,
+				?candidate_hash,
+				"failed to submit locally-signed dispute statement to the transaction pool",
+			);
+		}
+	}
+}
+
+/// The explicit-vote `DisputeStatement` for a locally-produced valid/invalid verdict.
+///
+/// Pulled out of `sign_and_submit_local_statement` so the valid/invalid -> statement-kind
+/// mapping is testable without a keystore, candidate receipt, or transaction pool in play.
+fn explicit_dispute_statement_kind(valid: bool) -> DisputeStatement {
+	if valid {
+		DisputeStatement::Valid(ValidDisputeStatementKind::Explicit)
+	} else {
+		DisputeStatement::Invalid(InvalidDisputeStatementKind::Explicit)
+	}
+}
+
+/// Builds the runtime-specific extrinsic that carries a locally-signed dispute statement
+/// on-chain. The concrete `Call` used to submit it lives in the crate that wires the runtime
+/// together, not here, so the subsystem is generic over how to build it.
+pub trait DisputeStatementExtrinsicBuilder<Block: BlockT>: Send + Sync {
+	fn build(&self, statement: SignedDisputeStatement, candidate_receipt: CandidateReceipt) -> Block::Extrinsic;
+}
+
+/// The dispute coordinator subsystem.
+pub struct DisputeCoordinatorSubsystem<Block: BlockT> {
+	keystore: Arc<LocalKeystore>,
+	db: Arc<dyn KeyValueDB>,
+	tx_pool_factory: OffchainTransactionPoolFactory<Block>,
+	extrinsic_builder: Arc<dyn DisputeStatementExtrinsicBuilder<Block>>,
+}
+
+impl<Block: BlockT> DisputeCoordinatorSubsystem<Block> {
+	/// Create a new instance of the subsystem, backed by `db` for vote persistence and
+	/// `tx_pool_factory` for broadcasting locally-produced dispute votes.
+	pub fn new(
+		keystore: Arc<LocalKeystore>,
+		db: Arc<dyn KeyValueDB>,
+		tx_pool_factory: OffchainTransactionPoolFactory<Block>,
+		extrinsic_builder: Arc<dyn DisputeStatementExtrinsicBuilder<Block>>,
+	) -> Self {
+		DisputeCoordinatorSubsystem { keystore, db, tx_pool_factory, extrinsic_builder }
+	}
+}
+
+impl<Context, Block: BlockT> Subsystem<Context> for DisputeCoordinatorSubsystem<Block>
+where
+	Context: SubsystemContext<Message = DisputeCoordinatorMessage>,
+{
+	fn start(self, ctx: Context) -> SpawnedSubsystem {
+		let future = run(ctx, self)
+			.map(|_| ())
+			.boxed();
+
+		SpawnedSubsystem {
+			name: "dispute-coordinator-subsystem",
+			future,
+		}
+	}
+}
+
+/// Ask the runtime for the session index that will apply to a child of `relay_parent`.
+///
+/// Returns `None` if the request couldn't be answered (a runtime API error, or the response
+/// channel being dropped), in which case the caller should simply keep whatever session index
+/// it already has rather than treat this as fatal.
+async fn request_session_index_for_child<Context, Block: BlockT>(
+	ctx: &mut Context,
+	relay_parent: Block::Hash,
+) -> Option<SessionIndex>
+where
+	Context: SubsystemContext<Message = DisputeCoordinatorMessage>,
+{
+	let (tx, rx) = oneshot::channel();
+	ctx.send_message(AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+		relay_parent,
+		RuntimeApiRequest::SessionIndexForChild(tx),
+	))).await;
+
+	match rx.await {
+		Ok(Ok(session)) => Some(session),
+		Ok(Err(err)) => {
+			tracing::warn!(target: LOG_TARGET, ?err, "runtime API error querying session index for child");
+			None
+		},
+		Err(_) => {
+			tracing::warn!(
+				target: LOG_TARGET,
+				"runtime API request for session index for child was dropped",
+			);
+			None
+		},
+	}
+}
+
+async fn run<Context, Block: BlockT>(mut ctx: Context, subsystem: DisputeCoordinatorSubsystem<Block>) -> SubsystemResult<()>
+where
+	Context: SubsystemContext<Message = DisputeCoordinatorMessage>,
+{
+	let DisputeCoordinatorSubsystem { keystore, db, tx_pool_factory, extrinsic_builder } = subsystem;
+
+	// `State` needs a real starting session index to reload the right slice of the persisted
+	// overlay (see `State::new`), so construction is deferred until the first active leaf gives
+	// us one, rather than guessing with a hardcoded value.
+	let mut state: Option<State<Block>> = None;
+	let mut best_block: Option<Block::Hash> = None;
+
+	loop {
+		match ctx.recv().await? {
+			FromOverseer::Signal(OverseerSignal::Conclude) => return Ok(()),
+			FromOverseer::Signal(OverseerSignal::ActiveLeaves(update)) => {
+				if let Some(activated) = update.activated {
+					best_block = Some(activated.hash);
+
+					if let Some(session) = request_session_index_for_child::<_, Block>(&mut ctx, activated.hash).await {
+						match state.as_mut() {
+							Some(state) => state.note_new_session(session),
+							None => {
+								state = Some(State::new(
+									keystore.clone(),
+									db.clone(),
+									tx_pool_factory.clone(),
+									extrinsic_builder.clone(),
+									session,
+								));
+							},
+						}
+					}
+				}
+			},
+			FromOverseer::Signal(OverseerSignal::BlockFinalized(_, _)) => {
+				if let Some(state) = state.as_mut() {
+					if let Err(err) = state.flush() {
+						tracing::warn!(target: LOG_TARGET, ?err, "failed to flush dispute votes to disk");
+					}
+				}
+			},
+			FromOverseer::Communication { msg } => {
+				if let Some(state) = state.as_mut() {
+					handle_incoming(state, best_block, msg);
+				}
+			},
+		}
+	}
+}
+
+fn handle_incoming<Block: BlockT>(
+	state: &mut State<Block>,
+	best_block: Option<Block::Hash>,
+	msg: DisputeCoordinatorMessage,
+) {
+	match msg {
+		DisputeCoordinatorMessage::IssueLocalStatement(session, _candidate_hash, candidate_receipt, valid) => {
+			match best_block {
+				Some(at) => state.sign_and_submit_local_statement(at, session, candidate_receipt, valid),
+				None => tracing::debug!(
+					target: LOG_TARGET,
+					"dropping local dispute statement issued before observing an active leaf",
+				),
+			}
+		},
+		// Vote import and query handling is implemented incrementally alongside the rest of
+		// `DisputeCoordinatorMessage`; nothing else is dispatched here yet.
+		_ => {},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::H256;
+
+	fn candidate(byte: u8) -> CandidateHash {
+		CandidateHash(H256::repeat_byte(byte))
+	}
+
+	#[test]
+	fn prune_ignores_sessions_at_or_below_the_current_high_water_mark() {
+		let mut overlay = HashMap::new();
+		overlay.insert((3, candidate(1)), CandidateVotes::default());
+		let mut highest_session = 5;
+		let mut earliest_session = 0;
+
+		prune_overlay_for_new_session(&mut overlay, &mut highest_session, &mut earliest_session, 5);
+		prune_overlay_for_new_session(&mut overlay, &mut highest_session, &mut earliest_session, 2);
+
+		assert_eq!(highest_session, 5);
+		assert_eq!(earliest_session, 0);
+		assert!(overlay.contains_key(&(3, candidate(1))));
+	}
+
+	#[test]
+	fn explicit_dispute_statement_kind_matches_the_local_verdict() {
+		assert_eq!(
+			explicit_dispute_statement_kind(true),
+			DisputeStatement::Valid(ValidDisputeStatementKind::Explicit),
+		);
+		assert_eq!(
+			explicit_dispute_statement_kind(false),
+			DisputeStatement::Invalid(InvalidDisputeStatementKind::Explicit),
+		);
+	}
+
+	#[test]
+	fn prune_drops_overlay_entries_outside_the_window_on_session_bump() {
+		let mut overlay = HashMap::new();
+		overlay.insert((1, candidate(1)), CandidateVotes::default());
+		overlay.insert((8, candidate(2)), CandidateVotes::default());
+		let mut highest_session = 1;
+		let mut earliest_session = 0;
+
+		// DISPUTE_WINDOW is 6, so bumping to session 8 should push earliest_session to 2 and
+		// drop the session-1 entry while keeping the session-8 one.
+		prune_overlay_for_new_session(&mut overlay, &mut highest_session, &mut earliest_session, 8);
+
+		assert_eq!(highest_session, 8);
+		assert_eq!(earliest_session, 2);
+		assert!(!overlay.contains_key(&(1, candidate(1))));
+		assert!(overlay.contains_key(&(8, candidate(2))));
+	}
 }
@@ -18,16 +18,44 @@
 
 use codec::{Encode, Decode};
 use sp_runtime::RuntimeDebug;
-use frame_support::{decl_event, decl_storage, decl_module, decl_error, ensure};
-use frame_support::traits::{EnsureOrigin, IsDeadAccount};
+use sp_runtime::traits::{Convert, One, Saturating, Zero};
+use frame_support::{decl_event, decl_storage, decl_module, decl_error, ensure, weights::Weight};
+use frame_support::traits::{Currency, EnsureOrigin, Get, IsDeadAccount, VestingSchedule};
+
+/// Balance type used by this module, taken from the configured `Currency`.
+pub type BalanceOf<T> = <<T as Trait>::Currency as Currency<<T as system::Trait>::AccountId>>::Balance;
 
 /// Configuration trait.
 pub trait Trait: system::Trait {
 	/// The overarching event type.
 	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
 	type ValidityOrigin: EnsureOrigin<Self::Origin>;
+	/// The currency used to hold and deliver purchased DOTs.
+	type Currency: Currency<Self::AccountId>;
+	/// The vesting schedule applied to unlocked balances.
+	type VestingSchedule: VestingSchedule<Self::AccountId, Moment = Self::BlockNumber, Currency = Self::Currency>;
+	/// Maximum total contribution allowed for a `ValidLow` account.
+	type LowCap: Get<BalanceOf<Self>>;
+	/// Maximum total contribution allowed for a `ValidHigh` account.
+	type HighCap: Get<BalanceOf<Self>>;
+	/// Number of blocks over which an unlocked contribution vests, linearly.
+	///
+	/// The per-block release rate is derived from this and the contribution amount, so accounts
+	/// finish vesting in roughly the same span regardless of how much they contributed (see
+	/// `per_block_vesting_rate` for the rounding).
+	type VestingDuration: Get<Self::BlockNumber>;
+	/// Converts `VestingDuration` into a `Balance` so a per-block vesting rate can be derived
+	/// from it, matching the same associated type on `pallet_vesting::Trait`.
+	type BlockNumberToBalance: Convert<Self::BlockNumber, BalanceOf<Self>>;
+	/// Upper bound on the sum of all recorded contributions, checked by `do_try_state`.
+	type SaleCap: Get<BalanceOf<Self>>;
 }
 
+/// Error type returned by `do_try_state` checks, only compiled in under the `try-runtime`
+/// feature, matching the convention used for invariant checks elsewhere in the ecosystem.
+#[cfg(feature = "try-runtime")]
+pub type TryRuntimeError = sp_runtime::DispatchError;
+
 /// The kind of a statement an account needs to make for a claim to be valid.
 #[derive(Encode, Decode, Clone, Copy, Eq, PartialEq, RuntimeDebug)]
 pub enum AccountValidity {
@@ -47,12 +75,49 @@ impl Default for AccountValidity {
 	}
 }
 
+/// A full KYC record backing an account's validity decision.
+///
+/// Unlike a bare `AccountValidity`, this ties the decision to the document that was actually
+/// reviewed (via its hash) and to the time window over which the decision is considered current,
+/// so re-verification can be enforced rather than assumed.
+#[derive(Encode, Decode, Clone, Eq, PartialEq, RuntimeDebug)]
+pub struct KycRecord<T: Trait> {
+	/// The tier this record grants, assuming it has not expired.
+	pub level: AccountValidity,
+	/// Hash of the KYC document that was reviewed to reach this decision.
+	pub document_hash: T::Hash,
+	/// Block at which this record was written.
+	pub verified_at: T::BlockNumber,
+	/// Block from which on this record no longer grants `level`, if any.
+	pub expires_at: Option<T::BlockNumber>,
+}
+
 decl_event!(
-	pub enum Event<T> where AccountId = <T as system::Trait>::AccountId {
+	pub enum Event<T> where
+		AccountId = <T as system::Trait>::AccountId,
+		Hash = <T as system::Trait>::Hash,
+		Balance = BalanceOf<T>,
+	{
 		/// Someone's account validity was updated
 		ValidityUpdated(AccountId, AccountValidity),
 		/// Someone's account validity statement was removed
 		ValidityRemoved(AccountId),
+		/// A KYC record was written for an account, carrying the hash of the document that was
+		/// reviewed so off-chain verifiers can audit which submission backed the decision.
+		KycRecordSet(AccountId, Hash),
+		/// An account's KYC record has passed its expiry block and no longer grants its tier.
+		ValidityExpired(AccountId),
+		/// A batch of validity statements was processed. `processed` accounts were dead and had
+		/// their validity set; `skipped` were already alive and left untouched.
+		BatchValidityUpdated(u32, u32),
+		/// An account's recorded contribution increased by the given amount.
+		ContributionProcessed(AccountId, Balance),
+		/// An account's recorded contribution was delivered and placed under vesting.
+		Unlocked(AccountId, Balance),
+		/// A proxy was authorized to act on a claimant's behalf.
+		ProxyAssigned(AccountId, AccountId),
+		/// A claimant's proxy authorization was removed.
+		ProxyRemoved(AccountId),
 	}
 );
 
@@ -60,12 +125,30 @@ decl_error! {
 	pub enum Error for Module<T: Trait> {
 		/// Account used in the crowdsale already exists.
 		ExistingAccount,
+		/// Account does not hold a current validity tier that allows contributing.
+		InvalidAccount,
+		/// Contribution would push the account's total over its tier's cap.
+		CapExceeded,
+		/// Account has no recorded contribution to unlock.
+		NoContribution,
+		/// Account has no validity record, so a proxy cannot be assigned to it.
+		NoValidityRecord,
+		/// Signing account is neither the claimant nor its registered proxy.
+		NotClaimantOrProxy,
 	}
 }
 
 decl_storage! {
 	trait Store for Module<T: Trait> as Crowdsale {
 		ValidityStatements: map hasher(blake2_128_concat) T::AccountId => AccountValidity;
+		/// Full KYC records, keyed by account. Accounts set only through the legacy
+		/// `set_account_validity` call will have an entry in `ValidityStatements` but not here.
+		KycRecords: map hasher(blake2_128_concat) T::AccountId => Option<KycRecord<T>>;
+		/// Total amount an account has been approved to purchase, awaiting `unlock`.
+		Contributions: map hasher(blake2_128_concat) T::AccountId => BalanceOf<T>;
+		/// An account authorized to trigger `unlock` on a claimant's behalf, for claimants
+		/// who cannot hold the key used during KYC (custodial and multisig participants).
+		ProxyFor: map hasher(blake2_128_concat) T::AccountId => Option<T::AccountId>;
 	}
 }
 
@@ -83,9 +166,262 @@ decl_module! {
 		fn set_account_validity(origin, who: T::AccountId, validity: AccountValidity) {
 			T::ValidityOrigin::ensure_origin(origin)?;
 			ensure!(system::Module::<T>::is_dead_account(&who), Error::<T>::ExistingAccount);
-			ValidityStatements::<T>::insert(&who, validity);
+			Self::do_set_validity(&who, validity);
 			Self::deposit_event(RawEvent::ValidityUpdated(who, validity));
 		}
+
+		/// Add a validity statement to a batch of accounts in a single call.
+		///
+		/// Origin must match the `ValidityOrigin`. Accounts that are not dead are skipped
+		/// (and counted) rather than aborting the whole batch, so a single bad entry in a
+		/// large compliance import doesn't lose the rest of the batch.
+		#[weight = T::DbWeight::get().reads_writes(items.len() as Weight, items.len() as Weight)]
+		fn set_account_validity_batch(origin, items: Vec<(T::AccountId, AccountValidity)>) {
+			T::ValidityOrigin::ensure_origin(origin)?;
+
+			let mut processed = 0u32;
+			let mut skipped = 0u32;
+			for (who, validity) in items {
+				if !system::Module::<T>::is_dead_account(&who) {
+					skipped = skipped.saturating_add(1);
+					continue;
+				}
+				Self::do_set_validity(&who, validity);
+				processed = processed.saturating_add(1);
+				Self::deposit_event(RawEvent::ValidityUpdated(who, validity));
+			}
+			Self::deposit_event(RawEvent::BatchValidityUpdated(processed, skipped));
+		}
+
+		/// Write a full KYC record for a specified account, tying its validity tier to the hash
+		/// of the document that was reviewed to grant it.
+		///
+		/// Origin must match the `ValidityOrigin`.
+		#[weight = 0]
+		fn set_kyc_record(
+			origin,
+			who: T::AccountId,
+			level: AccountValidity,
+			document_hash: T::Hash,
+			expires_at: Option<T::BlockNumber>,
+		) {
+			T::ValidityOrigin::ensure_origin(origin)?;
+			ensure!(system::Module::<T>::is_dead_account(&who), Error::<T>::ExistingAccount);
+
+			let verified_at = system::Module::<T>::block_number();
+			ValidityStatements::<T>::insert(&who, level);
+			KycRecords::<T>::insert(&who, KycRecord {
+				level,
+				document_hash,
+				verified_at,
+				expires_at,
+			});
+
+			Self::deposit_event(RawEvent::ValidityUpdated(who.clone(), level));
+			Self::deposit_event(RawEvent::KycRecordSet(who, document_hash));
+		}
+
+		/// Record a contribution against an already-validated account, enforcing the
+		/// per-tier cap configured for its validity level.
+		///
+		/// Origin must match the `ValidityOrigin`.
+		#[weight = 0]
+		fn process_contribution(origin, who: T::AccountId, amount: BalanceOf<T>) {
+			T::ValidityOrigin::ensure_origin(origin)?;
+			Self::check_expiry(&who);
+			ensure!(Self::is_valid(&who), Error::<T>::InvalidAccount);
+
+			let cap = match Self::tier_of(&who) {
+				AccountValidity::ValidLow => T::LowCap::get(),
+				AccountValidity::ValidHigh => T::HighCap::get(),
+				AccountValidity::Pending | AccountValidity::Invalid => return Err(Error::<T>::InvalidAccount.into()),
+			};
+
+			let total = Contributions::<T>::get(&who).saturating_add(amount);
+			ensure!(total <= cap, Error::<T>::CapExceeded);
+
+			Contributions::<T>::insert(&who, total);
+			Self::deposit_event(RawEvent::ContributionProcessed(who, amount));
+		}
+
+		/// Deliver an account's recorded contribution: create the account, deposit its
+		/// purchased balance, and place it under the configured vesting schedule.
+		///
+		/// May be signed by the claimant `who` itself, or by its registered proxy, so that
+		/// custodial and multisig participants who cannot hold the original KYC key can still
+		/// trigger delivery.
+		#[weight = 0]
+		fn unlock(origin, who: T::AccountId) {
+			let signer = system::ensure_signed(origin)?;
+			ensure!(
+				signer == who || ProxyFor::<T>::get(&who).as_ref() == Some(&signer),
+				Error::<T>::NotClaimantOrProxy,
+			);
+
+			Self::check_expiry(&who);
+			ensure!(Self::is_valid(&who), Error::<T>::InvalidAccount);
+
+			let amount = Contributions::<T>::get(&who);
+			ensure!(!amount.is_zero(), Error::<T>::NoContribution);
+
+			let _ = T::Currency::deposit_creating(&who, amount);
+			T::VestingSchedule::add_vesting_schedule(
+				&who,
+				amount,
+				Self::per_block_vesting_rate(amount),
+				system::Module::<T>::block_number(),
+			).map_err(|_| Error::<T>::NoContribution)?;
+
+			Contributions::<T>::remove(&who);
+			Self::deposit_event(RawEvent::Unlocked(who, amount));
+		}
+
+		/// Authorize `proxy` to call `unlock` on behalf of `claimant`, or remove its current
+		/// proxy if `proxy` is `None`.
+		///
+		/// Origin must match the `ValidityOrigin`. `claimant` must already have a validity
+		/// record.
+		#[weight = 0]
+		fn set_proxy(origin, claimant: T::AccountId, proxy: Option<T::AccountId>) {
+			T::ValidityOrigin::ensure_origin(origin)?;
+			ensure!(
+				KycRecords::<T>::contains_key(&claimant) || ValidityStatements::<T>::contains_key(&claimant),
+				Error::<T>::NoValidityRecord,
+			);
+
+			match proxy {
+				Some(proxy) => {
+					ProxyFor::<T>::insert(&claimant, &proxy);
+					Self::deposit_event(RawEvent::ProxyAssigned(claimant, proxy));
+				},
+				None => {
+					ProxyFor::<T>::remove(&claimant);
+					Self::deposit_event(RawEvent::ProxyRemoved(claimant));
+				},
+			}
+		}
+	}
+}
+
+impl<T: Trait> Module<T> {
+	/// The per-block vesting rate that spreads `amount` evenly over `T::VestingDuration`.
+	///
+	/// Deriving the rate from `amount` rather than using a single flat constant keeps the vesting
+	/// *duration* roughly constant across accounts regardless of contribution size; a flat
+	/// per-block rate would otherwise let a large `ValidHigh` contribution take far longer to
+	/// fully vest than a small `ValidLow` one. The rate is floored at `1` so a contribution
+	/// smaller than the configured duration still vests (over fewer blocks) rather than never
+	/// finishing.
+	fn per_block_vesting_rate(amount: BalanceOf<T>) -> BalanceOf<T> {
+		let duration = T::BlockNumberToBalance::convert(T::VestingDuration::get()).max(One::one());
+		(amount / duration).max(One::one())
+	}
+
+	/// Write `validity` for `who`, keeping any existing `KycRecord`'s tier in sync.
+	///
+	/// Without this, revoking (or re-grading) an account through the bare validity calls would
+	/// silently do nothing once that account also has a `KycRecord`: `is_valid`/`tier_of` prefer
+	/// the `KycRecord` whenever one exists, so a stale, non-expired record would keep granting
+	/// its old tier forever. Keeping the two in lockstep makes `set_account_validity` and
+	/// `set_account_validity_batch` a real revocation path regardless of how an account was
+	/// originally validated.
+	fn do_set_validity(who: &T::AccountId, validity: AccountValidity) {
+		ValidityStatements::<T>::insert(who, validity);
+		KycRecords::<T>::mutate(who, |maybe_record| {
+			if let Some(record) = maybe_record {
+				record.level = validity;
+			}
+		});
+	}
+
+	/// Whether `who` currently holds a non-expired validity tier above `Pending`.
+	///
+	/// Accounts with a `KycRecord` are valid only while their tier is `ValidLow`/`ValidHigh` and
+	/// `expires_at`, if set, has not yet passed. Accounts set only through the legacy
+	/// `set_account_validity` call (no `KycRecord`) fall back to their bare `ValidityStatements`
+	/// tier, which never expires.
+	pub fn is_valid(who: &T::AccountId) -> bool {
+		match KycRecords::<T>::get(who) {
+			Some(record) => {
+				let not_expired = record.expires_at
+					.map(|expires_at| system::Module::<T>::block_number() < expires_at)
+					.unwrap_or(true);
+				not_expired && Self::is_valid_tier(record.level)
+			},
+			None => Self::is_valid_tier(ValidityStatements::<T>::get(who)),
+		}
+	}
+
+	fn is_valid_tier(level: AccountValidity) -> bool {
+		matches!(level, AccountValidity::ValidLow | AccountValidity::ValidHigh)
+	}
+
+	/// The validity tier currently in effect for `who` (via its `KycRecord` if one exists and
+	/// has not expired, falling back to the bare `ValidityStatements` entry otherwise).
+	fn tier_of(who: &T::AccountId) -> AccountValidity {
+		match KycRecords::<T>::get(who) {
+			Some(record) => {
+				let expired = record.expires_at
+					.map(|expires_at| system::Module::<T>::block_number() >= expires_at)
+					.unwrap_or(false);
+				if expired { AccountValidity::Invalid } else { record.level }
+			},
+			None => ValidityStatements::<T>::get(who),
+		}
+	}
+
+	/// Lazily settle `who`'s `KycRecord` if it has expired: clears the record, resets the bare
+	/// `ValidityStatements` entry to `Invalid` (so there's no stale fallback tier once the
+	/// record is gone), and emits `ValidityExpired`.
+	///
+	/// Called from extrinsics that care whether `who` is currently valid, rather than from a
+	/// per-block sweep — with potentially thousands of KYC'd accounts, scanning all of
+	/// `KycRecords` on every block is unbounded work that never goes away, whereas this only
+	/// touches the one account actually being acted on.
+	fn check_expiry(who: &T::AccountId) {
+		let expired = KycRecords::<T>::get(who)
+			.and_then(|record| record.expires_at)
+			.map(|expires_at| system::Module::<T>::block_number() >= expires_at)
+			.unwrap_or(false);
+
+		if expired {
+			KycRecords::<T>::remove(who);
+			ValidityStatements::<T>::insert(who, AccountValidity::Invalid);
+			Self::deposit_event(RawEvent::ValidityExpired(who.clone()));
+		}
+	}
+
+	/// Defensive, warn-then-ensure invariant check intended to be run against forked state.
+	///
+	/// Asserts that no account with a non-`Invalid` validity tier is also a live (non-dead)
+	/// account, and that the sum of recorded contributions stays within `SaleCap`. Logs the
+	/// offending account/amount before failing, so a broken migration or manual storage edit
+	/// surfaces the specific corruption rather than a bare assertion.
+	#[cfg(feature = "try-runtime")]
+	pub fn do_try_state() -> Result<(), TryRuntimeError> {
+		for (who, level) in ValidityStatements::<T>::iter() {
+			if level != AccountValidity::Invalid && !system::Module::<T>::is_dead_account(&who) {
+				log::warn!(
+					target: "runtime::crowdsale",
+					"account {:?} has validity {:?} but already exists",
+					who, level,
+				);
+				return Err(TryRuntimeError::Other("crowdsale: validity set for an existing account"));
+			}
+		}
+
+		let total: BalanceOf<T> = Contributions::<T>::iter()
+			.fold(Zero::zero(), |acc, (_, amount)| acc.saturating_add(amount));
+		if total > T::SaleCap::get() {
+			log::warn!(
+				target: "runtime::crowdsale",
+				"total recorded contributions {:?} exceed the sale cap {:?}",
+				total, T::SaleCap::get(),
+			);
+			return Err(TryRuntimeError::Other("crowdsale: contributions exceed sale cap"));
+		}
+
+		Ok(())
 	}
 }
 
@@ -159,6 +495,10 @@ mod tests {
 		pub const ExistentialDeposit: u64 = 1;
 		pub const CreationFee: u64 = 0;
 		pub const MinVestedTransfer: u64 = 0;
+		pub const LowCap: u64 = 100;
+		pub const HighCap: u64 = 1_000;
+		pub const VestingDuration: u64 = 10;
+		pub const SaleCap: u64 = 10_000;
 	}
 
 	impl balances::Trait for Test {
@@ -169,12 +509,27 @@ mod tests {
 		type AccountStore = System;
 	}
 
+	impl vesting::Trait for Test {
+		type Event = ();
+		type Currency = Balances;
+		type BlockNumberToBalance = sp_runtime::traits::Identity;
+		type MinVestedTransfer = MinVestedTransfer;
+	}
+
 	impl Trait for Test {
 		type Event = ();
 		type ValidityOrigin = system::EnsureSignedBy<Six, u64>;
+		type Currency = Balances;
+		type VestingSchedule = Vesting;
+		type LowCap = LowCap;
+		type HighCap = HighCap;
+		type VestingDuration = VestingDuration;
+		type BlockNumberToBalance = sp_runtime::traits::Identity;
+		type SaleCap = SaleCap;
 	}
 	type System = system::Module<Test>;
 	type Balances = balances::Module<Test>;
+	type Vesting = vesting::Module<Test>;
 	type Crowdsale = Module<Test>;
 
 	// This function basically just builds a genesis storage key/value store according to
@@ -211,4 +566,253 @@ mod tests {
 			assert_noop!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidLow), Error::<Test>::ExistingAccount);
 		});
 	}
+
+	#[test]
+	fn set_account_validity_batch_works() {
+		new_test_ext().execute_with(|| {
+			// Make account 43 "alive" so it gets skipped.
+			Balances::make_free_balance_be(&43, 500);
+
+			assert_noop!(
+				Crowdsale::set_account_validity_batch(Origin::signed(1), vec![(42, AccountValidity::ValidLow)]),
+				BadOrigin,
+			);
+
+			assert_ok!(Crowdsale::set_account_validity_batch(Origin::signed(6), vec![
+				(42, AccountValidity::ValidLow),
+				(43, AccountValidity::ValidHigh),
+				(44, AccountValidity::ValidHigh),
+			]));
+			assert_eq!(ValidityStatements::<Test>::get(42), AccountValidity::ValidLow);
+			assert_eq!(ValidityStatements::<Test>::get(43), AccountValidity::Invalid);
+			assert_eq!(ValidityStatements::<Test>::get(44), AccountValidity::ValidHigh);
+		});
+	}
+
+	#[test]
+	fn process_contribution_enforces_tier_caps() {
+		new_test_ext().execute_with(|| {
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidLow));
+
+			// Must be valid to contribute.
+			assert_noop!(
+				Crowdsale::process_contribution(Origin::signed(6), 43, 10),
+				Error::<Test>::InvalidAccount,
+			);
+
+			assert_ok!(Crowdsale::process_contribution(Origin::signed(6), 42, 60));
+			assert_eq!(Contributions::<Test>::get(42), 60);
+			// Over the `ValidLow` cap of 100.
+			assert_noop!(
+				Crowdsale::process_contribution(Origin::signed(6), 42, 60),
+				Error::<Test>::CapExceeded,
+			);
+		});
+	}
+
+	#[test]
+	fn unlock_delivers_balance_and_vests_it() {
+		new_test_ext().execute_with(|| {
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert_ok!(Crowdsale::process_contribution(Origin::signed(6), 42, 500));
+
+			// Only the claimant (or their proxy) may trigger unlock.
+			assert_noop!(Crowdsale::unlock(Origin::signed(6), 42), Error::<Test>::NotClaimantOrProxy);
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 43, AccountValidity::ValidLow));
+			assert_noop!(Crowdsale::unlock(Origin::signed(43), 43), Error::<Test>::NoContribution);
+
+			assert_ok!(Crowdsale::unlock(Origin::signed(42), 42));
+			assert_eq!(Balances::free_balance(42), 500);
+			assert_eq!(Contributions::<Test>::get(42), 0);
+			// The balance is locked under vesting rather than immediately spendable.
+			assert!(!Balances::locks(42).is_empty());
+		});
+	}
+
+	#[test]
+	fn unlock_rejects_an_account_revoked_after_contribution() {
+		new_test_ext().execute_with(|| {
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert_ok!(Crowdsale::process_contribution(Origin::signed(6), 42, 500));
+
+			// Revoking after the contribution was approved must still block delivery.
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::Invalid));
+			assert_noop!(Crowdsale::unlock(Origin::signed(42), 42), Error::<Test>::InvalidAccount);
+
+			// The contribution is untouched, so re-validating the account lets it unlock later.
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert_ok!(Crowdsale::unlock(Origin::signed(42), 42));
+			assert_eq!(Balances::free_balance(42), 500);
+		});
+	}
+
+	#[test]
+	fn unlock_rejects_an_account_whose_kyc_record_expired() {
+		new_test_ext().execute_with(|| {
+			let document_hash = H256::repeat_byte(5);
+			assert_ok!(Crowdsale::set_kyc_record(
+				Origin::signed(6), 42, AccountValidity::ValidHigh, document_hash, Some(10),
+			));
+			assert_ok!(Crowdsale::process_contribution(Origin::signed(6), 42, 500));
+
+			System::set_block_number(10);
+			// The expired record is settled even though the call itself is rejected.
+			frame_support::assert_err!(Crowdsale::unlock(Origin::signed(42), 42), Error::<Test>::InvalidAccount);
+			assert!(KycRecords::<Test>::get(42).is_none());
+			assert_eq!(Contributions::<Test>::get(42), 500);
+		});
+	}
+
+	#[test]
+	fn per_block_vesting_rate_scales_with_contribution_size() {
+		// Both accounts vest over the same `VestingDuration`, so a 10x larger contribution
+		// should vest at a 10x higher per-block rate rather than taking 10x longer.
+		assert_eq!(Crowdsale::per_block_vesting_rate(100), 10);
+		assert_eq!(Crowdsale::per_block_vesting_rate(1_000), 100);
+	}
+
+	#[test]
+	fn per_block_vesting_rate_is_never_zero() {
+		// A contribution smaller than `VestingDuration` still vests, over fewer blocks, rather
+		// than flooring to a rate of `0` and never finishing.
+		assert_eq!(Crowdsale::per_block_vesting_rate(1), 1);
+	}
+
+	#[test]
+	#[cfg(feature = "try-runtime")]
+	fn do_try_state_catches_validity_on_existing_account() {
+		new_test_ext().execute_with(|| {
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidLow));
+			assert_ok!(Crowdsale::do_try_state());
+
+			// Account comes alive without going through `unlock`.
+			Balances::make_free_balance_be(&42, 500);
+			assert!(Crowdsale::do_try_state().is_err());
+		});
+	}
+
+	#[test]
+	#[cfg(feature = "try-runtime")]
+	fn do_try_state_catches_contributions_over_sale_cap() {
+		new_test_ext().execute_with(|| {
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert_ok!(Crowdsale::process_contribution(Origin::signed(6), 42, 1_000));
+			assert_ok!(Crowdsale::do_try_state());
+
+			Contributions::<Test>::insert(42, 20_000u64);
+			assert!(Crowdsale::do_try_state().is_err());
+		});
+	}
+
+	#[test]
+	fn set_proxy_requires_existing_validity_record() {
+		new_test_ext().execute_with(|| {
+			assert_noop!(
+				Crowdsale::set_proxy(Origin::signed(6), 42, Some(99)),
+				Error::<Test>::NoValidityRecord,
+			);
+
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert_noop!(
+				Crowdsale::set_proxy(Origin::signed(1), 42, Some(99)),
+				BadOrigin,
+			);
+			assert_ok!(Crowdsale::set_proxy(Origin::signed(6), 42, Some(99)));
+			assert_eq!(ProxyFor::<Test>::get(42), Some(99));
+
+			assert_ok!(Crowdsale::set_proxy(Origin::signed(6), 42, None));
+			assert_eq!(ProxyFor::<Test>::get(42), None);
+		});
+	}
+
+	#[test]
+	fn proxy_can_trigger_unlock_on_claimants_behalf() {
+		new_test_ext().execute_with(|| {
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert_ok!(Crowdsale::process_contribution(Origin::signed(6), 42, 500));
+			assert_ok!(Crowdsale::set_proxy(Origin::signed(6), 42, Some(99)));
+
+			// Some other account still can't unlock on the claimant's behalf.
+			assert_noop!(Crowdsale::unlock(Origin::signed(1), 42), Error::<Test>::NotClaimantOrProxy);
+
+			assert_ok!(Crowdsale::unlock(Origin::signed(99), 42));
+			assert_eq!(Balances::free_balance(42), 500);
+		});
+	}
+
+	#[test]
+	fn set_kyc_record_works() {
+		new_test_ext().execute_with(|| {
+			let document_hash = H256::repeat_byte(1);
+			// Origin must be the `ValidityOrigin`
+			assert_noop!(
+				Crowdsale::set_kyc_record(Origin::signed(1), 42, AccountValidity::ValidLow, document_hash, None),
+				BadOrigin,
+			);
+			assert_ok!(Crowdsale::set_kyc_record(
+				Origin::signed(6), 42, AccountValidity::ValidLow, document_hash, Some(10),
+			));
+			assert_eq!(ValidityStatements::<Test>::get(42), AccountValidity::ValidLow);
+			assert!(Crowdsale::is_valid(&42));
+
+			System::set_block_number(10);
+			// Expiry is exclusive of the block it names.
+			assert!(!Crowdsale::is_valid(&42));
+		});
+	}
+
+	#[test]
+	fn is_valid_falls_back_to_bare_validity_statement() {
+		new_test_ext().execute_with(|| {
+			// An account set through the legacy call has no `KycRecord` and so never expires.
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::ValidHigh));
+			assert!(Crowdsale::is_valid(&42));
+		});
+	}
+
+	#[test]
+	fn check_expiry_clears_record_on_exact_block() {
+		new_test_ext().execute_with(|| {
+			let document_hash = H256::repeat_byte(2);
+			assert_ok!(Crowdsale::set_kyc_record(
+				Origin::signed(6), 42, AccountValidity::ValidLow, document_hash, Some(10),
+			));
+			System::set_block_number(10);
+			Crowdsale::check_expiry(&42);
+			assert!(KycRecords::<Test>::get(42).is_none());
+			assert_eq!(ValidityStatements::<Test>::get(42), AccountValidity::Invalid);
+		});
+	}
+
+	#[test]
+	fn set_account_validity_revokes_kyced_account() {
+		new_test_ext().execute_with(|| {
+			let document_hash = H256::repeat_byte(3);
+			assert_ok!(Crowdsale::set_kyc_record(
+				Origin::signed(6), 42, AccountValidity::ValidHigh, document_hash, None,
+			));
+			assert!(Crowdsale::is_valid(&42));
+
+			assert_ok!(Crowdsale::set_account_validity(Origin::signed(6), 42, AccountValidity::Invalid));
+			assert!(!Crowdsale::is_valid(&42));
+			assert_eq!(KycRecords::<Test>::get(42).unwrap().level, AccountValidity::Invalid);
+		});
+	}
+
+	#[test]
+	fn set_account_validity_batch_revokes_kyced_account() {
+		new_test_ext().execute_with(|| {
+			let document_hash = H256::repeat_byte(4);
+			assert_ok!(Crowdsale::set_kyc_record(
+				Origin::signed(6), 42, AccountValidity::ValidHigh, document_hash, None,
+			));
+			assert!(Crowdsale::is_valid(&42));
+
+			assert_ok!(Crowdsale::set_account_validity_batch(
+				Origin::signed(6), vec![(42, AccountValidity::Invalid)],
+			));
+			assert!(!Crowdsale::is_valid(&42));
+			assert_eq!(KycRecords::<Test>::get(42).unwrap().level, AccountValidity::Invalid);
+		});
+	}
 }
\ No newline at end of file